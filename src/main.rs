@@ -5,23 +5,31 @@
 #![feature(transpose_result)]
 
 use ::atom_syndication as atom;
-use ::chrono;
+use ::chrono::{self, TimeZone};
 use ::env_logger;
 use ::failure;
-use ::serde_derive::Deserialize;
+use ::serde_derive::{Deserialize, Serialize};
 use ::serde_json;
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use ::actix_web::{
-    self, dev::AsyncResult, http, server, App, Either, HttpMessage, HttpResponse, Path, Responder,
+    self, dev::AsyncResult, http, server, App, Either, HttpMessage, HttpRequest, HttpResponse,
+    Path, Responder,
 };
 use ::failure::ResultExt;
 use ::futures_await::{self as futures, prelude::{await, async_block, *}};
+use ::log::{error, info};
 use ::scraper::{ElementRef, Html};
+use ::signal_hook::{self, iterator::Signals};
 use ::structopt::StructOpt;
+use ::tokio_timer::Timeout;
 use ::uuid::{self, Uuid};
 
 mod selector;
@@ -29,8 +37,14 @@ mod selector;
 use selector::{Selector, SelectorEx};
 
 const UA: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:58.0) Gecko/20100101 Firefox/58.0";
+const CACHE_MAX_AGE: u32 = 300;
+const DEFAULT_UPSTREAM_TIMEOUT_SECS: u64 = 15;
 
-static mut CONFIG: *const HashMap<String, Feed> = std::ptr::null();
+#[derive(Clone)]
+struct AppState {
+    config: Arc<RwLock<HashMap<String, Arc<Feed>>>>,
+    fetch_cache: Arc<Mutex<HashMap<String, CachedFeed>>>,
+}
 
 #[derive(StructOpt, Debug)]
 struct Opt {
@@ -57,21 +71,44 @@ struct Feed {
     entry_summary: Option<SelectorEx>,
     entry_updated: Option<SelectorEx>,
     entry_published: Option<SelectorEx>,
+    cache_ttl: Option<u64>,
+    /// strptime-style format for `entry_updated`/`entry_published`, e.g. "%b %d, %Y"
+    date_format: Option<String>,
+    /// hours east of UTC the source site's dates are printed in, defaults to 0 (UTC)
+    date_tz_offset_hours: Option<i32>,
+    /// fail the request instead of falling back to the raw string when `date_format` doesn't match
+    date_parse_strict: Option<bool>,
+    next_page: Option<SelectorEx>,
+    max_pages: Option<u32>,
+    /// seconds to wait for the upstream response before giving up, defaults to 15
+    timeout: Option<u64>,
+}
+
+#[derive(Clone)]
+struct CachedFeed {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    entries: Vec<atom::Entry>,
+    fetched_at: Instant,
 }
 
-fn get_config() -> &'static HashMap<String, Feed> {
-    unsafe { &*CONFIG }
+/// Key a fetch-cache entry on both the feed id and its current config, so editing
+/// a feed's `link`/selectors and reloading can't serve entries scraped under the
+/// old config.
+fn cache_key(id: &str, feed_cfg: &Feed) -> String {
+    let mut hasher = DefaultHasher::new();
+    format!("{:?}", feed_cfg).hash(&mut hasher);
+    format!("{}:{:x}", id, hasher.finish())
 }
 
-fn init_config(path: &str) -> Result<(), failure::Error> {
+fn load_config(path: &str) -> Result<HashMap<String, Arc<Feed>>, failure::Error> {
     let config: HashMap<String, Feed> = serde_json::from_reader(
         File::open(path).context(format!("Failed to open config file: {}", path))?,
     ).context(format!("Failed to parse config file: {}", path))?;
-    unsafe {
-        // Put on the heap to make it 'static
-        CONFIG = Box::into_raw(Box::new(config));
-    }
-    Ok(())
+    Ok(config
+        .into_iter()
+        .map(|(id, feed)| (id, Arc::new(feed)))
+        .collect())
 }
 
 fn select(entry_element: &ElementRef, selector: &SelectorEx) -> Result<String, actix_web::Error> {
@@ -94,6 +131,54 @@ fn select(entry_element: &ElementRef, selector: &SelectorEx) -> Result<String, a
     Ok(r)
 }
 
+fn normalize_date(feed_cfg: &Feed, raw: String) -> Result<String, actix_web::Error> {
+    let format = match &feed_cfg.date_format {
+        Some(format) => format,
+        None => return Ok(raw),
+    };
+    let offset_hours = feed_cfg.date_tz_offset_hours.unwrap_or(0);
+    let offset = chrono::FixedOffset::east_opt(offset_hours * 3600).ok_or_else(|| {
+        actix_web::error::ErrorInternalServerError(format!(
+            "date_tz_offset_hours {} is out of range (must be between -23 and 23)",
+            offset_hours
+        ))
+    })?;
+
+    // Try a timezone-aware parse first (for `date_format`s with an embedded
+    // `%z`/`%:z` offset) before falling back to naive parsing against the
+    // separately configured `date_tz_offset_hours`.
+    let parsed = chrono::DateTime::parse_from_str(&raw, format).ok().or_else(|| {
+        chrono::NaiveDateTime::parse_from_str(&raw, format)
+            .or_else(|_| chrono::NaiveDate::parse_from_str(&raw, format).map(|d| d.and_hms(0, 0, 0)))
+            .ok()
+            .and_then(|naive| offset.from_local_datetime(&naive).single())
+    });
+
+    match parsed {
+        Some(dt) => Ok(dt.to_rfc3339()),
+        None if feed_cfg.date_parse_strict.unwrap_or(false) => Err(
+            actix_web::error::ErrorInternalServerError(format!(
+                "failed to parse date \"{}\" with format \"{}\"",
+                raw, format
+            )),
+        ),
+        None => Ok(raw),
+    }
+}
+
+fn resolve_link(base: &str, mut l: String) -> String {
+    if l.starts_with('?') {
+        let base_without_query = base.split('?').next().unwrap_or(base);
+        return base_without_query.to_owned() + &l;
+    }
+    if l.starts_with(&['/', '.'][..]) {
+        l = base.to_owned() + &l;
+    } else if !l.starts_with("http") {
+        l = base.to_owned() + "/" + &l;
+    }
+    l
+}
+
 fn fill_entry(entry_element: ElementRef, feed_cfg: &Feed) -> Result<atom::Entry, actix_web::Error> {
     let mut entry = atom::Entry::default();
     let title = select(&entry_element, &feed_cfg.entry_title)?;
@@ -103,14 +188,9 @@ fn fill_entry(entry_element: ElementRef, feed_cfg: &Feed) -> Result<atom::Entry,
         .as_ref()
         .map(|s| select(&entry_element, s))
         .transpose()?
-        .map(|mut l| {
+        .map(|l| {
             let mut link = atom::Link::default();
-            if l.starts_with(&['/', '.'][..]) {
-                l = feed_cfg.link.to_owned() + &l;
-            } else if !l.starts_with("http") {
-                l = feed_cfg.link.to_owned() + "/" + &l;
-            }
-            link.set_href(l);
+            link.set_href(resolve_link(&feed_cfg.link, l));
             link
         });
     entry.set_links(link.into_iter().collect::<Vec<atom::Link>>());
@@ -140,55 +220,362 @@ fn fill_entry(entry_element: ElementRef, feed_cfg: &Feed) -> Result<atom::Entry,
         .entry_updated
         .as_ref()
         .map(|s| select(&entry_element, s))
+        .transpose()?
+        .map(|raw| normalize_date(feed_cfg, raw))
         .transpose()?;
     entry.set_updated(updated.unwrap_or_else(|| String::new()));
     let published = feed_cfg
         .entry_published
         .as_ref()
         .map(|s| select(&entry_element, s))
+        .transpose()?
+        .map(|raw| normalize_date(feed_cfg, raw))
         .transpose()?;
     entry.set_published(published);
     Ok(entry)
 }
 
+fn send_timeout_error<E>(err: ::tokio_timer::timeout::Error<E>) -> actix_web::Error
+where
+    E: Into<actix_web::Error>,
+{
+    if err.is_elapsed() {
+        actix_web::error::ErrorGatewayTimeout("upstream request timed out")
+    } else if let Some(err) = err.into_inner() {
+        err.into()
+    } else {
+        actix_web::error::ErrorInternalServerError("timer error")
+    }
+}
+
+fn body_timeout_error<E: std::fmt::Display>(err: ::tokio_timer::timeout::Error<E>) -> actix_web::Error {
+    if err.is_elapsed() {
+        actix_web::error::ErrorGatewayTimeout("timed out reading upstream response body")
+    } else {
+        let msg = err
+            .into_inner()
+            .map(|err| err.to_string())
+            .unwrap_or_else(|| "timer error".to_string());
+        actix_web::error::ErrorBadGateway(format!("upstream response body error: {}", msg))
+    }
+}
+
+fn fetch_entries(
+    id: String,
+    feed_cfg: Arc<Feed>,
+    fetch_cache: Arc<Mutex<HashMap<String, CachedFeed>>>,
+) -> impl Future<Item = Vec<atom::Entry>, Error = actix_web::Error> {
+    async_block! {
+        let key = cache_key(&id, &feed_cfg);
+        let cached = fetch_cache.lock().unwrap().get(&key).cloned();
+        let ttl = feed_cfg.cache_ttl.unwrap_or(0);
+        if let Some(cached) = &cached {
+            if ttl > 0 && cached.fetched_at.elapsed() < Duration::from_secs(ttl) {
+                return Ok(cached.entries.clone());
+            }
+        }
+
+        let timeout = Duration::from_secs(
+            feed_cfg.timeout.unwrap_or(DEFAULT_UPSTREAM_TIMEOUT_SECS),
+        );
+
+        let mut req = actix_web::client::get(&feed_cfg.link).header("User-Agent", UA);
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.etag {
+                req = req.header(http::header::IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                req = req.header(http::header::IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+        let resp = await!(Timeout::new(
+            req.finish().expect("request builder").send(),
+            timeout
+        ))
+        .map_err(send_timeout_error)?;
+
+        if resp.status() == http::StatusCode::NOT_MODIFIED {
+            let cached = cached.ok_or_else(|| {
+                actix_web::error::ErrorBadGateway("origin sent 304 with no cached entry")
+            })?;
+            fetch_cache.lock().unwrap().insert(
+                key,
+                CachedFeed {
+                    fetched_at: Instant::now(),
+                    ..cached.clone()
+                },
+            );
+            return Ok(cached.entries);
+        }
+
+        if !resp.status().is_success() {
+            return Err(actix_web::error::ErrorBadGateway(format!(
+                "upstream returned {}",
+                resp.status()
+            )));
+        }
+
+        let etag = resp
+            .headers()
+            .get(http::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned);
+        let last_modified = resp
+            .headers()
+            .get(http::header::LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        let mut page_body = await!(Timeout::new(resp.body().limit(524_288), timeout))
+            .map_err(body_timeout_error)?;
+        let max_pages = feed_cfg.max_pages.unwrap_or(1).max(1);
+
+        let mut entries = Vec::new();
+        let mut seen_ids = std::collections::HashSet::new();
+        let mut current_url = feed_cfg.link.clone();
+        for page in 0..max_pages {
+            let html = Html::parse_document(&String::from_utf8_lossy(&page_body));
+
+            for entry_element in html.select(&feed_cfg.entries) {
+                let entry = fill_entry(entry_element, &feed_cfg)?;
+                if seen_ids.insert(entry.id().to_owned()) {
+                    entries.push(entry);
+                }
+            }
+
+            if page + 1 >= max_pages {
+                break;
+            }
+            let next_page = match &feed_cfg.next_page {
+                Some(selector) => select(&html.root_element(), selector).ok(),
+                None => None,
+            };
+            let next_page = match next_page {
+                Some(next_page) => next_page,
+                None => break,
+            };
+
+            current_url = resolve_link(&current_url, next_page);
+            let next_resp = await!(Timeout::new(
+                actix_web::client::get(&current_url)
+                    .header("User-Agent", UA)
+                    .finish()
+                    .expect("request builder")
+                    .send(),
+                timeout
+            ));
+            let next_resp = match next_resp {
+                Ok(next_resp) => next_resp,
+                Err(err) => {
+                    error!("failed to fetch next page for feed {}: {:?}", id, err);
+                    break;
+                }
+            };
+            if !next_resp.status().is_success() {
+                break;
+            }
+            page_body = match await!(Timeout::new(next_resp.body().limit(524_288), timeout)) {
+                Ok(page_body) => page_body,
+                Err(err) => {
+                    error!("failed to read next page body for feed {}: {:?}", id, err);
+                    break;
+                }
+            };
+        }
+        if entries.is_empty() {
+            return Err(actix_web::error::ErrorInternalServerError("entries selector"));
+        }
+
+        fetch_cache.lock().unwrap().insert(
+            key,
+            CachedFeed {
+                etag,
+                last_modified,
+                entries: entries.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+
+        Ok(entries)
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem {
+    id: String,
+    title: String,
+    url: Option<String>,
+    // `entry_summary` doesn't distinguish HTML from plain text at the source, so
+    // the scraped summary is published under both JSON Feed fields.
+    content_html: Option<String>,
+    summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_published: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_modified: Option<String>,
+    authors: Vec<JsonFeedAuthor>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedDocument {
+    version: &'static str,
+    title: String,
+    home_page_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+fn json_feed_for(feed_cfg: &Feed, feed: &atom::Feed) -> JsonFeedDocument {
+    let items = feed
+        .entries()
+        .iter()
+        .map(|entry| JsonFeedItem {
+            id: entry.id().to_owned(),
+            title: entry.title().to_owned(),
+            url: entry.links().first().map(|l| l.href().to_owned()),
+            content_html: entry.summary().map(ToOwned::to_owned),
+            summary: entry.summary().map(ToOwned::to_owned),
+            date_published: entry.published().map(ToOwned::to_owned),
+            date_modified: if entry.updated().is_empty() {
+                None
+            } else {
+                Some(entry.updated().to_owned())
+            },
+            authors: entry
+                .authors()
+                .iter()
+                .map(|author| JsonFeedAuthor {
+                    name: author.name().to_owned(),
+                })
+                .collect(),
+        })
+        .collect();
+
+    JsonFeedDocument {
+        version: "https://jsonfeed.org/version/1.1",
+        title: feed_cfg.title.clone(),
+        home_page_url: feed_cfg.link.clone(),
+        items,
+    }
+}
+
+fn wants_json<S>(id: &str, req: &HttpRequest<S>) -> bool {
+    if id.ends_with(".json") {
+        return true;
+    }
+    req.headers()
+        .get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("application/feed+json") || v.contains("application/json"))
+        .unwrap_or(false)
+}
+
+fn etag_for(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
+
+fn entries_updated(entries: &[atom::Entry]) -> String {
+    entries
+        .iter()
+        .filter_map(|entry| chrono::DateTime::parse_from_rfc3339(entry.updated()).ok())
+        .max()
+        .map(|dt| dt.to_rfc3339())
+        .unwrap_or_else(|| "1970-01-01T00:00:00+00:00".to_string())
+}
+
+/// Unlike `entries_updated`, this has no epoch fallback: a feed with no entry
+/// carrying a real date has nothing to substantiate a `Last-Modified` with, so
+/// callers should omit the header and rely on the content-hash ETag instead.
+fn last_modified_for(entries: &[atom::Entry]) -> Option<String> {
+    entries
+        .iter()
+        .filter_map(|entry| chrono::DateTime::parse_from_rfc3339(entry.updated()).ok())
+        .max()
+        .map(|dt| dt.to_rfc2822())
+}
+
+fn not_modified<S>(req: &HttpRequest<S>, etag: &str, last_modified: &Option<String>) -> bool {
+    if let Some(if_none_match) = req.headers().get(http::header::IF_NONE_MATCH) {
+        return if_none_match.to_str().map(|v| v == etag).unwrap_or(false);
+    }
+    if let (Some(if_modified_since), Some(last_modified)) =
+        (req.headers().get(http::header::IF_MODIFIED_SINCE), last_modified)
+    {
+        let since = if_modified_since
+            .to_str()
+            .ok()
+            .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok());
+        let last_modified = chrono::DateTime::parse_from_rfc2822(last_modified).ok();
+        if let (Some(since), Some(last_modified)) = (since, last_modified) {
+            return last_modified <= since;
+        }
+    }
+    false
+}
+
 fn index(
-    info: Path<String>,
+    (info, req): (Path<String>, HttpRequest<AppState>),
 ) -> impl Responder<Item = AsyncResult<HttpResponse>, Error = actix_web::Error> {
-    if let Some(feed_cfg) = get_config().get(&*info) {
+    let raw_id = (*info).clone();
+    let json = wants_json(&raw_id, &req);
+    let id = raw_id.trim_end_matches(".json").to_string();
+
+    let feed_cfg = req.state().config.read().unwrap().get(&id).cloned();
+    let fetch_cache = req.state().fetch_cache.clone();
+
+    if let Some(feed_cfg) = feed_cfg {
         Either::B(Box::new(async_block! {
-            let resp = await!(actix_web::client::get(&feed_cfg.link)
-                              .header("User-Agent", UA)
-                              .finish()
-                              .expect("request builder")
-                              .send())?;
-
-            if !resp.status().is_success() {
-                // error
-            }
-            let body = await!(resp.body().limit(524_288))?;
-            let html = Html::parse_document(&String::from_utf8_lossy(&body));
+            let entries = await!(fetch_entries(id.clone(), feed_cfg.clone(), fetch_cache))?;
 
             let mut feed = atom::Feed::default();
             feed.set_title(feed_cfg.title.clone());
             feed.set_subtitle(feed_cfg.subtitle.clone());
-            feed.set_updated(chrono::Local::now().to_rfc3339());
-            feed.set_id(Uuid::new_v5(&uuid::NAMESPACE_URL, &*info).urn().to_string());
+            feed.set_updated(entries_updated(&entries));
+            feed.set_id(Uuid::new_v5(&uuid::NAMESPACE_URL, &*id).urn().to_string());
             // feed.set_generator();
 
             let mut link = atom::Link::default();
             link.set_href(feed_cfg.link.clone());
             feed.set_links(vec![link]);
 
-            let mut entries = Vec::new();
-            for entry_element in html.select(&feed_cfg.entries) {
-                let entry = fill_entry(entry_element, &feed_cfg)?;
-                entries.push(entry);
+            let last_modified = last_modified_for(&entries);
+            feed.set_entries(entries);
+
+            let (content_type, body) = if json {
+                let json_feed = json_feed_for(&feed_cfg, &feed);
+                let body = serde_json::to_string(&json_feed)
+                    .map_err(actix_web::error::ErrorInternalServerError)?;
+                ("application/feed+json", body)
+            } else {
+                ("application/xml", feed.to_string())
+            };
+            let etag = etag_for(&body);
+
+            let is_not_modified = not_modified(&req, &etag, &last_modified);
+            let mut resp = if is_not_modified {
+                HttpResponse::NotModified()
+            } else {
+                HttpResponse::Ok()
+            };
+            resp.header(http::header::ETAG, etag);
+            resp.header(
+                http::header::CACHE_CONTROL,
+                format!("max-age={}", CACHE_MAX_AGE),
+            );
+            if let Some(last_modified) = last_modified {
+                resp.header(http::header::LAST_MODIFIED, last_modified);
             }
-            if entries.is_empty() {
-                return Err(actix_web::error::ErrorInternalServerError("entries selector"));
+            if is_not_modified {
+                Ok(resp.finish())
+            } else {
+                Ok(resp.content_type(content_type).body(body))
             }
-            feed.set_entries(entries);
-            Ok(HttpResponse::Ok().content_type("application/xml").body(feed.to_string()))
         })
             as Box<Future<Item = HttpResponse, Error = actix_web::Error>>)
     } else {
@@ -206,10 +593,38 @@ fn main() -> Result<(), failure::Error> {
         );
     }
     env_logger::init();
-    init_config(&opt.config)?;
 
-    server::new(|| {
-        App::new()
+    let config = Arc::new(RwLock::new(load_config(&opt.config)?));
+    let fetch_cache = Arc::new(Mutex::new(HashMap::new()));
+    let state = AppState {
+        config: config.clone(),
+        fetch_cache: fetch_cache.clone(),
+    };
+
+    {
+        let config = config.clone();
+        let fetch_cache = fetch_cache.clone();
+        let config_path = opt.config.clone();
+        let signals = Signals::new(&[signal_hook::SIGHUP])
+            .context("Failed to register SIGHUP handler")?;
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                match load_config(&config_path) {
+                    Ok(new_config) => {
+                        *config.write().unwrap() = new_config;
+                        // cache keys are derived from feed config, but drop the whole
+                        // cache here too so removed/renamed feeds don't linger forever
+                        fetch_cache.lock().unwrap().clear();
+                        info!("reloaded config from {}", config_path);
+                    }
+                    Err(err) => error!("failed to reload config from {}: {}", config_path, err),
+                }
+            }
+        });
+    }
+
+    server::new(move || {
+        App::with_state(state.clone())
             .middleware(actix_web::middleware::Logger::default())
             .route("/{id}", http::Method::GET, index)
     }).bind(opt.addr)
@@ -217,3 +632,161 @@ fn main() -> Result<(), failure::Error> {
     .run();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_with(extra: &str) -> Feed {
+        let json = format!(
+            r#"{{"title":"t","link":"http://example.com/feed","entries":"div","entry_title":"h1"{}}}"#,
+            extra
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn normalize_date_parses_naive_format_with_configured_offset() {
+        let feed_cfg = feed_with(
+            r#","date_format":"%Y-%m-%d %H:%M:%S","date_tz_offset_hours":8"#,
+        );
+        let got = normalize_date(&feed_cfg, "2024-01-02 15:04:05".to_string()).unwrap();
+        assert_eq!(got, "2024-01-02T15:04:05+08:00");
+    }
+
+    #[test]
+    fn normalize_date_parses_embedded_timezone_offset() {
+        let feed_cfg = feed_with(r#","date_format":"%Y-%m-%d %H:%M:%S %z""#);
+        let got = normalize_date(&feed_cfg, "2024-01-02 15:04:05 +0800".to_string()).unwrap();
+        assert_eq!(got, "2024-01-02T15:04:05+08:00");
+    }
+
+    #[test]
+    fn normalize_date_rejects_out_of_range_offset() {
+        let feed_cfg = feed_with(
+            r#","date_format":"%Y-%m-%d","date_tz_offset_hours":25"#,
+        );
+        let err = normalize_date(&feed_cfg, "2024-01-02".to_string()).unwrap_err();
+        assert!(format!("{}", err).contains("out of range"));
+    }
+
+    #[test]
+    fn normalize_date_falls_back_to_raw_when_not_strict() {
+        let feed_cfg = feed_with(r#","date_format":"%Y-%m-%d""#);
+        let got = normalize_date(&feed_cfg, "not a date".to_string()).unwrap();
+        assert_eq!(got, "not a date");
+    }
+
+    #[test]
+    fn normalize_date_errors_when_strict() {
+        let feed_cfg = feed_with(
+            r#","date_format":"%Y-%m-%d","date_parse_strict":true"#,
+        );
+        assert!(normalize_date(&feed_cfg, "not a date".to_string()).is_err());
+    }
+
+    #[test]
+    fn resolve_link_query_only_keeps_base_path() {
+        let got = resolve_link("http://example.com/list?page=1", "?page=2".to_string());
+        assert_eq!(got, "http://example.com/list?page=2");
+    }
+
+    #[test]
+    fn resolve_link_absolute_path_is_appended_to_base() {
+        let got = resolve_link("http://example.com/list", "/item/1".to_string());
+        assert_eq!(got, "http://example.com/list/item/1");
+    }
+
+    #[test]
+    fn resolve_link_http_is_left_untouched() {
+        let got = resolve_link("http://example.com/list", "http://other.com/item/1".to_string());
+        assert_eq!(got, "http://other.com/item/1");
+    }
+
+    #[test]
+    fn resolve_link_plain_relative_is_joined_with_slash() {
+        let got = resolve_link("http://example.com/list", "item/1".to_string());
+        assert_eq!(got, "http://example.com/list/item/1");
+    }
+
+    fn entry_with_updated(updated: &str) -> atom::Entry {
+        let mut entry = atom::Entry::default();
+        entry.set_updated(updated.to_string());
+        entry
+    }
+
+    #[test]
+    fn entries_updated_picks_max_entry_date() {
+        let entries = vec![
+            entry_with_updated("2024-01-01T00:00:00+00:00"),
+            entry_with_updated("2024-06-01T00:00:00+00:00"),
+        ];
+        assert_eq!(entries_updated(&entries), "2024-06-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn entries_updated_falls_back_to_epoch_when_no_real_dates() {
+        let entries = vec![entry_with_updated("")];
+        assert_eq!(entries_updated(&entries), "1970-01-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn last_modified_for_is_none_without_real_entry_dates() {
+        let entries = vec![entry_with_updated("")];
+        assert!(last_modified_for(&entries).is_none());
+    }
+
+    #[test]
+    fn last_modified_for_some_with_real_entry_dates() {
+        let entries = vec![entry_with_updated("2024-01-01T00:00:00+00:00")];
+        assert!(last_modified_for(&entries).is_some());
+    }
+
+    #[test]
+    fn not_modified_true_when_etag_matches() {
+        let req = actix_web::test::TestRequest::with_header(
+            http::header::IF_NONE_MATCH.as_str(),
+            "\"abc\"",
+        ).finish();
+        assert!(not_modified(&req, "\"abc\"", &None));
+    }
+
+    #[test]
+    fn not_modified_false_when_etag_differs() {
+        let req = actix_web::test::TestRequest::with_header(
+            http::header::IF_NONE_MATCH.as_str(),
+            "\"abc\"",
+        ).finish();
+        assert!(!not_modified(&req, "\"def\"", &None));
+    }
+
+    #[test]
+    fn not_modified_true_when_not_modified_since() {
+        let req = actix_web::test::TestRequest::with_header(
+            http::header::IF_MODIFIED_SINCE.as_str(),
+            "Tue, 01 Jan 2030 00:00:00 GMT",
+        ).finish();
+        let last_modified = Some("Mon, 01 Jan 2024 00:00:00 GMT".to_string());
+        assert!(not_modified(&req, "\"etag\"", &last_modified));
+    }
+
+    #[test]
+    fn not_modified_false_without_conditional_headers() {
+        let req = actix_web::test::TestRequest::default().finish();
+        assert!(!not_modified(&req, "\"etag\"", &None));
+    }
+
+    #[test]
+    fn cache_key_changes_when_feed_config_changes() {
+        let a = feed_with("");
+        let b = feed_with(r#","date_format":"%Y-%m-%d""#);
+        assert_ne!(cache_key("feed", &a), cache_key("feed", &b));
+    }
+
+    #[test]
+    fn cache_key_stable_for_same_id_and_config() {
+        let a = feed_with("");
+        let b = feed_with("");
+        assert_eq!(cache_key("feed", &a), cache_key("feed", &b));
+    }
+}